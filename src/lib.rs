@@ -9,13 +9,15 @@
  * - LOST
  *
  * Player score text label should be store in player struct.
- *
- * Finish implementation of new_position_rad().
  */
 
 use rusty_engine::prelude::*;
 use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
 use std::collections::VecDeque;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
 use core::f32::consts::*;
 
 const MAX_NR_PLAYERS: usize = 4;
@@ -51,10 +53,36 @@ const PLAYER_LOOSE_TIMEOUT: f32 = 5.0;
 /* How many virtual pixels to move player each time */
 const PLAYER_MOVE_DISTANCE: f32 = 10.0;
 
+/* How fast a held turn key rotates the heading, in radians per second */
+const TURN_RATE: f32 = 3.0;
+
+/* How long an empty player slot waits for a human before an AI takes it over */
+const AI_JOIN_TIMEOUT: f32 = 10.0;
+
+/* How far ahead (scaled by PLAYER_MOVE_DISTANCE) the AI looks for danger */
+const AI_DANGER_RADIUS: f32 = PLAYER_MOVE_DISTANCE * 1.5;
+
 const PLAYER_STARTING_MAX_LEN: usize = 4;
 
 const PILL_SPAWN_INTERVAL: f32 = 3.0;
 
+/* Nr of entries kept in the persistent high-score table */
+const LEADERBOARD_SIZE: usize = 10;
+
+const LEADERBOARD_FILE_NAME: &str = "highscores.json";
+
+const LEADERBOARD_LABEL: &str = "leaderboard";
+
+/* How long the "Player N wins!" banner is shown before the round resets */
+const ROUND_OVER_DURATION: f32 = 5.0;
+
+const ROUND_OVER_LABEL: &str = "round-over-banner";
+
+/* Key that writes the current match's replay_log to disk, see save_replay() */
+const REPLAY_SAVE_KEY: KeyCode = KeyCode::R;
+
+const REPLAY_FILE_NAME: &str = "last-replay.json";
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
 enum Direction {
     UP,
@@ -70,6 +98,104 @@ enum PlayerState {
     LOST,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum PlayerController {
+    Human,
+    Ai,
+}
+
+/* Overall match flow, driven once per GameState::update() */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum GameStatus {
+    Lobby,
+    Running,
+    RoundOver,
+}
+
+/* One tick's worth of player input, as consumed by GameState::update() */
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+enum PlayerCommand {
+    Nothing,
+    TurnLeft,
+    TurnRight,
+    Join,
+}
+
+/* One tick's worth of recorded input: the commands plus the frame delta they were issued
+ * with, so replaying them reproduces the match regardless of the replaying run's own
+ * frame timing. */
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct RecordedTick {
+    delta_secs: f32,
+    commands: [PlayerCommand; MAX_NR_PLAYERS],
+}
+
+/* A recorded match: the RNG seed plus every tick's input, enough to reproduce it exactly */
+#[derive(Debug, Serialize, Deserialize)]
+struct Replay {
+    seed: u64,
+    ticks: Vec<RecordedTick>,
+}
+
+/* One entry in the persistent high-score table */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct HighScore {
+    score: usize,
+    timestamp: u64,
+}
+
+/* Top-N high scores, serialized to LEADERBOARD_FILE_NAME in the platform config dir */
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+struct Leaderboard {
+    entries: Vec<HighScore>,
+}
+
+/* Per-player accounting, accumulated by GameState::update() while a player is playing */
+#[derive(Debug, Default, Clone, Copy)]
+struct PlayerStats {
+    distance: f32,
+    pills: usize,
+    ticks: usize,
+    collisions: usize,
+}
+
+fn leaderboard_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rusty-snake")
+        .join(LEADERBOARD_FILE_NAME)
+}
+
+fn replay_path() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("rusty-snake")
+        .join(REPLAY_FILE_NAME)
+}
+
+fn load_leaderboard() -> Leaderboard {
+    std::fs::read_to_string(leaderboard_path())
+        .ok()
+        .and_then(|data| serde_json::from_str(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_leaderboard(leaderboard: &Leaderboard) {
+    let path = leaderboard_path();
+    if let Some(dir) = path.parent() {
+        if let Err(e) = std::fs::create_dir_all(dir) {
+            println!("Failed to create leaderboard dir {:?}: {}", dir, e);
+            return;
+        }
+    }
+    match std::fs::File::create(&path) {
+        Ok(file) => if let Err(e) = serde_json::to_writer(file, leaderboard) {
+            println!("Failed to write leaderboard {:?}: {}", path, e);
+        },
+        Err(e) => println!("Failed to create leaderboard file {:?}: {}", path, e),
+    }
+}
+
 struct Player {
     /* Static for each instance */
     idx: usize,
@@ -85,8 +211,11 @@ struct Player {
     max_len: usize,
     serial: usize,
     direction: Direction,
+    heading: f32,
     state: PlayerState,
     loose_timeout: Timer,
+    controller: PlayerController,
+    idle_timeout: Timer,
 }
 
 impl Player {
@@ -103,8 +232,11 @@ impl Player {
             max_len: PLAYER_STARTING_MAX_LEN,
             serial: 0,
             direction: Direction::RIGHT,
+            heading: 0.0,
             state: PlayerState::WAITING,
             loose_timeout: Timer::from_seconds(PLAYER_LOOSE_TIMEOUT, false),
+            controller: PlayerController::Human,
+            idle_timeout: Timer::from_seconds(AI_JOIN_TIMEOUT, false),
         }
     }
 
@@ -130,8 +262,11 @@ impl Player {
         self.max_len = PLAYER_STARTING_MAX_LEN;
         self.serial = 0;
         self.direction = self.starting_direction;
+        self.heading = 0.0;
         self.state = PlayerState::WAITING;
         self.loose_timeout = Timer::from_seconds(PLAYER_LOOSE_TIMEOUT, false);
+        self.controller = PlayerController::Human;
+        self.idle_timeout = Timer::from_seconds(AI_JOIN_TIMEOUT, false);
     }
 
     fn activate(self: &mut Self) {
@@ -149,21 +284,21 @@ impl Obstacle {
     const MIN_SCALE: f32 = 0.2;
     const MAX_SCALE: f32 = 1.2;
 
-    fn new(engine: &mut Engine, idx: usize) -> Obstacle{
+    fn new(engine: &mut Engine, idx: usize, rng: &mut StdRng) -> Obstacle{
         let x = engine.window_dimensions.x / 2.0;
         let y = engine.window_dimensions.y / 2.0;
-        let scale = thread_rng().gen_range(Obstacle::MIN_SCALE..Obstacle::MAX_SCALE);
+        let scale = rng.gen_range(Obstacle::MIN_SCALE..Obstacle::MAX_SCALE);
         let obstacle = Obstacle {
             label: format!("obstacle{}", idx),
             speed: Vec2 {
-                x: thread_rng().gen_range(-(2.0/scale)..(2.0/scale)),
-                y: thread_rng().gen_range(-(2.0/scale)..(2.0/scale)),
+                x: rng.gen_range(-(2.0/scale)..(2.0/scale)),
+                y: rng.gen_range(-(2.0/scale)..(2.0/scale)),
             },
         };
 
         println!("Obstacle::new() -> {:?}", obstacle);
         let obstacle_sprite = engine.add_sprite(obstacle.label.clone(), SpritePreset::RacingBarrelRed);
-        obstacle_sprite.translation = Vec2{x: thread_rng().gen_range((-x + 20.0)..(x - 20.0)), y: thread_rng().gen_range((-y + 20.0)..(y - 20.0))};
+        obstacle_sprite.translation = Vec2{x: rng.gen_range((-x + 20.0)..(x - 20.0)), y: rng.gen_range((-y + 20.0)..(y - 20.0))};
         obstacle_sprite.collision = true;
         obstacle_sprite.scale = scale;
         obstacle
@@ -190,26 +325,12 @@ impl Obstacle {
     }
 
     fn do_move(self: &mut Self, engine: &mut Engine) {
-        let max_x = engine.window_dimensions.x / 2.0;
-        let max_y = engine.window_dimensions.y / 2.0;
         let self_sprite = engine.sprites.get_mut(&self.label).unwrap();
-        let mut new_pos = Vec2 {
+        let new_pos = Vec2 {
             x: self_sprite.translation.x + self.speed.x,
             y: self_sprite.translation.y + self.speed.y,
         };
-        if new_pos.x > max_x {
-            new_pos.x = -max_x;
-        }
-        if new_pos.x < -max_x {
-            new_pos.x = max_x;
-        }
-        if new_pos.y > max_y {
-            new_pos.y = -max_y;
-        }
-        if new_pos.y < -max_y {
-            new_pos.y = max_y;
-        }
-        self_sprite.translation = new_pos;
+        self_sprite.translation = wrap_position(new_pos, engine.window_dimensions);
     }
 }
 
@@ -242,10 +363,41 @@ struct GameState {
 
     /* Players */
     player: [Player; MAX_NR_PLAYERS],
+
+    /* RNG driving obstacle/pill spawning, seeded so a match can be replayed exactly */
+    rng: StdRng,
+
+    /* Seed `rng` was created from, saved alongside recorded commands for replay */
+    seed: u64,
+
+    /* Input recorded each tick for save_replay(), None while replaying a recording */
+    replay_log: Option<Vec<RecordedTick>>,
+
+    /* Input queued for playback when replaying a recording, None during live play */
+    replay_queue: Option<VecDeque<RecordedTick>>,
+
+    /* Persistent top-N score table, loaded from disk on startup */
+    leaderboard: Leaderboard,
+
+    /* Set whenever `leaderboard` changes, so it's only written back on an actual change */
+    leaderboard_dirty: bool,
+
+    /* Per-player stats for the player's current life, reset on (re)activation */
+    stats: [PlayerStats; MAX_NR_PLAYERS],
+
+    /* Overall match flow: Lobby -> Running -> RoundOver -> Lobby */
+    status: GameStatus,
+
+    /* Counts down the "Player N wins!" banner before the round resets */
+    round_over_timer: Timer,
+
+    /* Which players have joined since the round started, to detect a last-player-standing win */
+    round_joined: [bool; MAX_NR_PLAYERS],
 }
 
 impl Default for GameState {
     fn default() -> Self {
+        let seed: u64 = thread_rng().gen();
         GameState {
             first_iteration: true,
             obstacles: Vec::new(),
@@ -261,10 +413,113 @@ impl Default for GameState {
                 Player::new(2),
                 Player::new(3),
             ],
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+            replay_log: Some(Vec::new()),
+            replay_queue: None,
+            leaderboard: load_leaderboard(),
+            leaderboard_dirty: false,
+            stats: [PlayerStats::default(); MAX_NR_PLAYERS],
+            status: GameStatus::Lobby,
+            round_over_timer: Timer::from_seconds(ROUND_OVER_DURATION, false),
+            round_joined: [false; MAX_NR_PLAYERS],
         }
     }
 }
 
+impl GameState {
+    /* Rebuild a GameState that replays a previously recorded match exactly */
+    fn from_replay(replay: Replay) -> Self {
+        let mut state = GameState::default();
+        state.rng = StdRng::seed_from_u64(replay.seed);
+        state.seed = replay.seed;
+        state.replay_log = None;
+        state.replay_queue = Some(VecDeque::from(replay.ticks));
+        state
+    }
+
+    /* Compute a player's final score from their accumulated stats, show it, record it on
+     * the leaderboard and return them to WAITING. Used both when a player's own loose_timeout
+     * fires and when a round ends while they're still PLAYING (i.e. they're the winner). */
+    fn score_and_deactivate(self: &mut Self, engine: &mut Engine, idx: usize, x: f32, y: f32) {
+        let stats = self.stats[idx];
+        let score = stats.pills * 10;
+        let seconds = stats.ticks as f32 * PLAYER_MOVE_TIMER_START;
+
+        let player = &mut self.player[idx];
+        let player_text = engine.add_text(player.score_label.clone(), format!(
+            "Player {}: {} pts, {} pills, {}px, survived {:.0}s",
+            player.idx, score, stats.pills, stats.distance as i32, seconds
+        ));
+        player_text.translation = Vec2::new(-x + 100.0 + (player.idx as f32 * 100.0), y - 50.0);
+        player_text.scale = 0.4;
+        engine.sprites.remove(&player.head_label);
+        for label in &player.labels {
+            engine.sprites.remove(label);
+        }
+        player.deactivate();
+
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let entries = &mut self.leaderboard.entries;
+        // A score that doesn't beat last place on a full table leaves the table unchanged
+        if entries.len() < LEADERBOARD_SIZE || entries.last().map_or(true, |last| score > last.score) {
+            entries.push(HighScore { score, timestamp });
+            entries.sort_by(|a, b| b.score.cmp(&a.score));
+            entries.truncate(LEADERBOARD_SIZE);
+            self.leaderboard_dirty = true;
+        }
+    }
+
+    /* Put a WAITING player into play: activate them, reset their stats for this life and
+     * spawn their head sprite at their starting position. Used both when a human joins and
+     * when an idle slot is taken over by an AI. */
+    fn spawn_player(self: &mut Self, engine: &mut Engine, idx: usize) {
+        let player = &mut self.player[idx];
+        player.activate();
+        let _ = engine.texts.remove(&player.score_label);
+        let head = engine.add_sprite(&player.head_label, player.sprite);
+        head.translation = player.starting_position;
+        head.collision = true;
+        head.scale = PLAYER_SCALE_HEAD;
+
+        self.stats[idx] = PlayerStats::default();
+        self.round_joined[idx] = true;
+    }
+
+    /* (Re)draw the persistent high-score table next to the live score labels */
+    fn render_leaderboard(self: &Self, engine: &mut Engine) {
+        let x = engine.window_dimensions.x / 2.0;
+        let y = engine.window_dimensions.y / 2.0;
+
+        let mut value = String::from("High Scores\n");
+        for (rank, entry) in self.leaderboard.entries.iter().enumerate() {
+            value.push_str(&format!("{}. {}\n", rank + 1, entry.score));
+        }
+
+        if let Some(label) = engine.texts.get_mut(LEADERBOARD_LABEL) {
+            label.value = value;
+        } else {
+            let label = engine.add_text(LEADERBOARD_LABEL, value);
+            label.translation = Vec2::new(-x + 100.0, y - 200.0);
+            label.scale = 0.35;
+        }
+    }
+}
+
+/* Write the commands recorded so far, plus the seed needed to reproduce them, to `path` */
+fn save_replay(state: &GameState, path: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let replay = Replay {
+        seed: state.seed,
+        ticks: state.replay_log.clone().unwrap_or_default(),
+    };
+    if let Some(dir) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let file = std::fs::File::create(path)?;
+    serde_json::to_writer(file, &replay)?;
+    Ok(())
+}
+
 pub fn start_game() {
     let mut game = Game::new();
 
@@ -281,6 +536,25 @@ pub fn start_game() {
     game.run(state);
 }
 
+/* Replay a match recorded with save_replay(), reproducing it tick for tick */
+pub fn start_game_replay(path: &str) {
+    let data = std::fs::read_to_string(path).expect("failed to read replay file");
+    let replay: Replay = serde_json::from_str(&data).expect("failed to parse replay file");
+
+    let mut game = Game::new();
+    let state = GameState::from_replay(replay);
+
+    game.window_settings(WindowDescriptor {
+        title: "Snake (replay)".into(),
+        ..Default::default()
+    });
+
+    game.audio_manager.play_music(MusicPreset::Classy8Bit, 0.1);
+
+    game.add_logic(replay_game_logic);
+    game.run(state);
+}
+
 fn new_direction(curr_dir: Direction, turn_left: bool) -> Direction {
     match curr_dir {
         Direction::UP    => if turn_left { Direction::LEFT } else { Direction::RIGHT },
@@ -290,177 +564,472 @@ fn new_direction(curr_dir: Direction, turn_left: bool) -> Direction {
     }
 }
 
+/* Wrap a position back onto the opposite edge of the play field once it moves past it */
+fn wrap_position(pos: Vec2, window_dimensions: Vec2) -> Vec2 {
+    let max_x = window_dimensions.x / 2.0;
+    let max_y = window_dimensions.y / 2.0;
+    let mut wrapped = pos;
+    if wrapped.x > max_x {
+        wrapped.x = -max_x;
+    }
+    if wrapped.x < -max_x {
+        wrapped.x = max_x;
+    }
+    if wrapped.y > max_y {
+        wrapped.y = -max_y;
+    }
+    if wrapped.y < -max_y {
+        wrapped.y = max_y;
+    }
+    wrapped
+}
+
 fn new_position(engine: &Engine, pos: Vec2, dir: Direction, speed: f32) -> Vec2 {
-    let mut new_pos = match dir {
+    let new_pos = match dir {
         Direction::UP    => Vec2 { x: pos.x,            y: pos.y + speed },
         Direction::RIGHT => Vec2 { x: pos.x + speed, y: pos.y },
         Direction::DOWN  => Vec2 { x: pos.x,            y: pos.y - speed },
         Direction::LEFT  => Vec2 { x: pos.x - speed, y: pos.y },
     };
-    let max_x = engine.window_dimensions.x / 2.0;
-    let max_y = engine.window_dimensions.y / 2.0;
-    if new_pos.x > max_x {
-        new_pos.x = -max_x;
-    }
-    if new_pos.x < -max_x {
-        new_pos.x = max_x;
-    }
-    if new_pos.y > max_y {
-        new_pos.y = -max_y;
-    }
-    if new_pos.y < -max_y {
-        new_pos.y = max_y;
+    wrap_position(new_pos, engine.window_dimensions)
+}
+
+fn new_position_rad(engine: &Engine, pos: Vec2, heading: f32, speed: f32) -> Vec2 {
+    let new_pos = Vec2 {
+        x: pos.x + speed * heading.cos(),
+        y: pos.y + speed * heading.sin(),
+    };
+    wrap_position(new_pos, engine.window_dimensions)
+}
+
+/* Heading (radians) that points exactly along a cardinal Direction */
+fn direction_heading(dir: Direction) -> f32 {
+    match dir {
+        Direction::RIGHT => 0.0,
+        Direction::UP    => FRAC_PI_2,
+        Direction::LEFT  => PI,
+        Direction::DOWN  => -FRAC_PI_2,
     }
-    new_pos
 }
 
-fn game_logic(engine: &mut Engine, state: &mut GameState) {
-    if state.first_iteration {
-        let nr_obstacles = 3;
+/* Owning player index of a "player-tail{idx}.{serial}" sprite label */
+fn tail_owner(label: &str) -> Option<usize> {
+    label.strip_prefix("player-tail")?.split('.').next()?.parse().ok()
+}
+
+/* A round is over once more than one player has joined and at most one is still standing */
+fn should_end_round(joined_count: usize, playing_count: usize) -> bool {
+    joined_count > 1 && playing_count <= 1
+}
+
+/* Greedy pill-seeking AI: try straight/left/right, discard moves that would hit an
+ * obstacle or any snake's tail (including this snake's own - colliding with it is just
+ * as fatal), and pick whichever safe move ends up closest to a pill. Falls back to
+ * going straight if nothing is safe. */
+fn ai_choose_direction(engine: &Engine, players: &[Player; MAX_NR_PLAYERS], idx: usize, head_pos: Vec2) -> Direction {
+    let player = &players[idx];
 
-        println!("Nr obstacles: {}", nr_obstacles);
+    let candidates = [
+        player.direction,
+        new_direction(player.direction, true),
+        new_direction(player.direction, false),
+    ];
 
-        for _ in 0..nr_obstacles {
+    let mut best: Option<(Direction, f32)> = None;
+
+    // The newest tail segment sits one move behind the head: within AI_DANGER_RADIUS of
+    // either turn, yet out of reach of every candidate move, so it isn't counted as danger
+    let neck = player.labels.front();
+
+    for &dir in &candidates {
+        let next_pos = new_position(engine, head_pos, dir, PLAYER_MOVE_DISTANCE);
+
+        let in_danger = engine.sprites.iter().any(|(label, sprite)| {
+            let dangerous = label.starts_with("obstacle") || tail_owner(label).is_some();
+            dangerous && Some(label) != neck && (sprite.translation - next_pos).length() < AI_DANGER_RADIUS
+        });
+
+        if in_danger {
+            continue;
         }
 
-        state.first_iteration = false;
+        let nearest_pill = engine.sprites.iter()
+            .filter(|(label, _)| label.starts_with("pill"))
+            .map(|(_, sprite)| (sprite.translation - next_pos).length())
+            .fold(f32::MAX, f32::min);
+
+        if best.map_or(true, |(_, best_dist)| nearest_pill < best_dist) {
+            best = Some((dir, nearest_pill));
+        }
     }
 
-    let x = engine.window_dimensions.x / 2.0;
-    let y = engine.window_dimensions.y / 2.0;
+    best.map(|(dir, _)| dir).unwrap_or(player.direction)
+}
+
+/* Translate key presses into this tick's commands; does not touch GameState */
+fn poll_commands(engine: &Engine, state: &GameState) -> [PlayerCommand; MAX_NR_PLAYERS] {
+    let mut commands = [PlayerCommand::Nothing; MAX_NR_PLAYERS];
 
-    if state.obstacle_move_timer.tick(engine.delta).just_finished() {
-        for obstacle in state.obstacles.iter_mut() {
-            obstacle.do_move(engine);
+    for (idx, player) in state.player.iter().enumerate() {
+        if player.is_playing() {
+            if engine.keyboard_state.pressed(player.control[0]) {
+                commands[idx] = PlayerCommand::TurnLeft;
+            } else if engine.keyboard_state.pressed(player.control[1]) {
+                commands[idx] = PlayerCommand::TurnRight;
+            }
+        } else if player.is_waiting() && engine.keyboard_state.just_pressed_any(&player.control) {
+            commands[idx] = PlayerCommand::Join;
         }
     }
 
-    if state.obstacle_next_timer.tick(engine.delta).just_finished() {
-        state.obstacle_next_timer = Timer::from_seconds(thread_rng().gen_range(2.0..10.0), false);
-        state.obstacles.push(Obstacle::new(engine, state.obstacle_serial));
-        state.obstacle_serial += 1;
-    }
+    commands
+}
 
-    // Check if it's time to add a pill
-    if state.pill_timer.tick(engine.delta).just_finished() {
-        let label = format!("pill{}", state.pill_idx);
-        state.pill_idx += 1;
+fn game_logic(engine: &mut Engine, state: &mut GameState) {
+    let commands = poll_commands(engine, state);
+
+    if let Some(log) = state.replay_log.as_mut() {
+        log.push(RecordedTick { delta_secs: engine.delta.as_secs_f32(), commands });
+    }
 
-        let pill = engine.add_sprite(label, SpritePreset::RacingBarrelBlue);
-        pill.translation.x = thread_rng().gen_range(-(x+20.0)..(x-20.0));
-        pill.translation.y = thread_rng().gen_range(-(y+20.0)..(y-20.0));
-        pill.collision = true;
+    if engine.keyboard_state.just_pressed(REPLAY_SAVE_KEY) {
+        let path = replay_path();
+        match save_replay(state, &path.to_string_lossy()) {
+            Ok(()) => println!("Replay saved to {:?}", path),
+            Err(e) => println!("Failed to save replay {:?}: {}", path, e),
+        }
     }
 
-    // Check if it's time for players to move
-    if state.player_move_timer.tick(engine.delta).just_finished() {
-        state.player_move_timer = Timer::from_seconds(PLAYER_MOVE_TIMER_START, false);
+    state.update(engine, commands);
+}
 
-        for (idx, player) in state.player.iter_mut().enumerate() {
-            if ! player.is_playing() {
-                continue;
+/* Drives GameState::update() from a recorded command queue instead of the keyboard.
+ * Overrides engine.delta with the recorded per-tick value so the replay reproduces the
+ * original match regardless of this run's own frame timing. */
+fn replay_game_logic(engine: &mut Engine, state: &mut GameState) {
+    let commands = match state.replay_queue.as_mut().and_then(VecDeque::pop_front) {
+        Some(tick) => {
+            engine.delta = std::time::Duration::from_secs_f32(tick.delta_secs);
+            tick.commands
+        }
+        None => [PlayerCommand::Nothing; MAX_NR_PLAYERS],
+    };
+
+    state.update(engine, commands);
+}
+
+impl GameState {
+    fn update(&mut self, engine: &mut Engine, commands: [PlayerCommand; MAX_NR_PLAYERS]) {
+        if self.first_iteration {
+            let nr_obstacles = 3;
+
+            println!("Nr obstacles: {}", nr_obstacles);
+
+            for _ in 0..nr_obstacles {
             }
 
-            let head_old_pos = engine.sprites.get(&player.head_label).unwrap().translation;
-            let head_new_pos = new_position(&engine, head_old_pos, player.direction, PLAYER_MOVE_DISTANCE);
-            let head_sprite = engine.sprites.get_mut(&player.head_label).unwrap();
-            head_sprite.translation = head_new_pos;
-
-            let tail_label = format!("player-tail{}.{}", idx, player.serial);
-            player.serial += 1;
-            let add_tail = engine.add_sprite(tail_label.clone(), player.sprite);
-            add_tail.translation = head_old_pos;
-            add_tail.collision = true;
-            add_tail.scale = PLAYER_SCALE_TAIL;
-            player.labels.push_front(tail_label);
-            if player.labels.len() > player.max_len {
-                engine.sprites.remove(&player.labels.pop_back().unwrap());
+            self.render_leaderboard(engine);
+            self.first_iteration = false;
+        }
+
+        let x = engine.window_dimensions.x / 2.0;
+        let y = engine.window_dimensions.y / 2.0;
+
+        match self.status {
+            GameStatus::Lobby => {
+                if self.player.iter().any(|player| player.is_playing()) {
+                    self.status = GameStatus::Running;
+                }
+            }
+            GameStatus::Running => {
+                let playing = self.player.iter().filter(|player| player.is_playing()).count();
+                let joined = self.round_joined.iter().filter(|&&joined| joined).count();
+                if should_end_round(joined, playing) {
+                    let banner = match self.player.iter().find(|player| player.is_playing()) {
+                        Some(winner) => format!("Player {} wins!", winner.idx),
+                        None => "No winner!".to_string(),
+                    };
+                    let banner_text = engine.add_text(ROUND_OVER_LABEL, banner);
+                    banner_text.translation = Vec2::new(0.0, 0.0);
+                    banner_text.scale = 1.0;
+
+                    self.round_over_timer = Timer::from_seconds(ROUND_OVER_DURATION, false);
+                    self.status = GameStatus::RoundOver;
+                }
+            }
+            GameStatus::RoundOver => {
+                if self.round_over_timer.tick(engine.delta).just_finished() {
+                    engine.texts.remove(ROUND_OVER_LABEL);
+
+                    // The winner is still PLAYING at this point; route them through the same
+                    // scoring/leaderboard path as everyone else before wiping the scene.
+                    for idx in 0..MAX_NR_PLAYERS {
+                        if self.player[idx].is_playing() {
+                            self.score_and_deactivate(engine, idx, x, y);
+                        }
+                    }
+
+                    engine.sprites.clear();
+
+                    for player in self.player.iter_mut() {
+                        player.deactivate();
+                    }
+                    self.obstacles.clear();
+                    self.obstacle_serial = 0;
+                    self.obstacle_next_timer = Timer::from_seconds(OBSTACLE_SPAWN_INTERVAL, true);
+                    self.pill_idx = 0;
+                    self.pill_timer = Timer::from_seconds(PILL_SPAWN_INTERVAL, true);
+                    self.round_joined = [false; MAX_NR_PLAYERS];
+
+                    self.status = GameStatus::Lobby;
+                }
             }
         }
-    }
 
-    // Check for key-presses, includes detecting a new player
-    for player in &mut state.player {
-        if engine.keyboard_state.just_pressed_any(&player.control) {
-            if player.is_playing() {
-                player.direction = new_direction(player.direction, engine.keyboard_state.pressed(player.control[0]));
-            } else if player.is_waiting() {
-                player.activate();
-                let _ = engine.texts.remove(&player.score_label);
-                let head = engine.add_sprite(&player.head_label, player.sprite);
-                head.translation = player.starting_position;
-                head.collision = true;
-                head.scale = PLAYER_SCALE_HEAD;
+        if self.status != GameStatus::RoundOver && self.obstacle_move_timer.tick(engine.delta).just_finished() {
+            for obstacle in self.obstacles.iter_mut() {
+                obstacle.do_move(engine);
             }
         }
-    }
 
-    for player in state.player.iter_mut() {
-        if ! player.has_lost() {
-            continue;
+        if self.status != GameStatus::RoundOver && self.obstacle_next_timer.tick(engine.delta).just_finished() {
+            self.obstacle_next_timer = Timer::from_seconds(self.rng.gen_range(2.0..10.0), false);
+            self.obstacles.push(Obstacle::new(engine, self.obstacle_serial, &mut self.rng));
+            self.obstacle_serial += 1;
+        }
+
+        // Check if it's time to add a pill
+        if self.status != GameStatus::RoundOver && self.pill_timer.tick(engine.delta).just_finished() {
+            let label = format!("pill{}", self.pill_idx);
+            self.pill_idx += 1;
+
+            let pill = engine.add_sprite(label, SpritePreset::RacingBarrelBlue);
+            pill.translation.x = self.rng.gen_range(-(x+20.0)..(x-20.0));
+            pill.translation.y = self.rng.gen_range(-(y+20.0)..(y-20.0));
+            pill.collision = true;
+        }
+
+        // Check if it's time for players to move; frozen during the RoundOver banner so the
+        // winner can't run into a leftover obstacle or their own tail before being scored
+        if self.status != GameStatus::RoundOver && self.player_move_timer.tick(engine.delta).just_finished() {
+            self.player_move_timer = Timer::from_seconds(PLAYER_MOVE_TIMER_START, false);
+
+            let mut ai_directions = [None; MAX_NR_PLAYERS];
+            for (idx, player) in self.player.iter().enumerate() {
+                if player.is_playing() && player.controller == PlayerController::Ai {
+                    let head_pos = engine.sprites.get(&player.head_label).unwrap().translation;
+                    ai_directions[idx] = Some(ai_choose_direction(engine, &self.player, idx, head_pos));
+                }
+            }
+
+            for (idx, player) in self.player.iter_mut().enumerate() {
+                if ! player.is_playing() {
+                    continue;
+                }
+
+                if let Some(dir) = ai_directions[idx] {
+                    player.direction = dir;
+                    player.heading = direction_heading(dir).rem_euclid(TAU);
+                }
+
+                let head_old_pos = engine.sprites.get(&player.head_label).unwrap().translation;
+                let head_new_pos = new_position_rad(&engine, head_old_pos, player.heading, PLAYER_MOVE_DISTANCE);
+                let head_sprite = engine.sprites.get_mut(&player.head_label).unwrap();
+                head_sprite.translation = head_new_pos;
+                head_sprite.rotation = player.heading;
+
+                let tail_label = format!("player-tail{}.{}", idx, player.serial);
+                player.serial += 1;
+                let add_tail = engine.add_sprite(tail_label.clone(), player.sprite);
+                add_tail.translation = head_old_pos;
+                add_tail.collision = true;
+                add_tail.scale = PLAYER_SCALE_TAIL;
+                player.labels.push_front(tail_label);
+                if player.labels.len() > player.max_len {
+                    engine.sprites.remove(&player.labels.pop_back().unwrap());
+                }
+
+                self.stats[idx].distance += PLAYER_MOVE_DISTANCE;
+                self.stats[idx].ticks += 1;
+            }
         }
 
-        if player.loose_timeout.tick(engine.delta).just_finished() {
-            let player_text = engine.add_text(player.score_label.clone(), format!("Player {}: {} points", player.idx, player.labels.len() * 10));
-            player_text.translation = Vec2::new(-x + 100.0 + (player.idx as f32 * 100.0), y - 50.0);
-            player_text.scale = 0.4;
-            engine.sprites.remove(&player.head_label);
-            for label in &player.labels {
-                engine.sprites.remove(label);
+        // Apply this tick's commands, includes detecting a new player
+        for idx in 0..MAX_NR_PLAYERS {
+            match commands[idx] {
+                PlayerCommand::TurnLeft if self.player[idx].is_playing() => {
+                    let heading = self.player[idx].heading;
+                    self.player[idx].heading = (heading - TURN_RATE * engine.delta.as_secs_f32()).rem_euclid(TAU);
+                }
+                PlayerCommand::TurnRight if self.player[idx].is_playing() => {
+                    let heading = self.player[idx].heading;
+                    self.player[idx].heading = (heading + TURN_RATE * engine.delta.as_secs_f32()).rem_euclid(TAU);
+                }
+                PlayerCommand::Join if self.player[idx].is_waiting() && self.status != GameStatus::RoundOver => {
+                    self.spawn_player(engine, idx);
+                }
+                _ => {}
             }
-            player.deactivate();
         }
-    }
 
-    // Handle collisions
-    for event in engine.collision_events.drain(..) {
-        if event.state.is_end() {
-            continue;
+        // Idle slots get taken over by an AI opponent after a short timeout
+        for idx in 0..MAX_NR_PLAYERS {
+            let timed_out = {
+                let player = &mut self.player[idx];
+                player.is_waiting() && player.controller == PlayerController::Human
+                    && self.status != GameStatus::RoundOver
+                    && player.idle_timeout.tick(engine.delta).just_finished()
+            };
+
+            if timed_out {
+                self.player[idx].controller = PlayerController::Ai;
+                self.spawn_player(engine, idx);
+            }
         }
 
-        if event.pair.one_starts_with("player-head") {
-            println!("Collision with player: {:?}", event.pair);
-            // Figure out which side is the player and which is what the player collided with
-            let player_label;
-            let colliding_with_label;
-            if event.pair.0.starts_with("player-head") {
-                player_label = event.pair.0;
-                colliding_with_label = event.pair.1;
-            } else {
-                player_label = event.pair.1;
-                colliding_with_label = event.pair.0;
+        for idx in 0..MAX_NR_PLAYERS {
+            if !self.player[idx].has_lost() {
+                continue;
             }
 
-            // Get player object based on label name
-            let player = &mut state.player[(player_label.strip_prefix("player-head").unwrap().chars().nth(0).unwrap() as u8 - '0' as u8) as usize];
-
-            // If pill, then eat it, otherwise loose
-            if colliding_with_label.starts_with("pill") {
-                player.max_len += 1;
-                engine.sprites.remove(&colliding_with_label);
-                engine.audio_manager.play_sfx(SfxPreset::Confirmation1, 0.2);
-            } else {
-                player.lost();
-                engine.audio_manager.play_sfx(SfxPreset::Impact1, 0.2);
-                println!("{} lost", player_label);
+            if self.player[idx].loose_timeout.tick(engine.delta).just_finished() {
+                self.score_and_deactivate(engine, idx, x, y);
             }
-        } else if event.pair.0.starts_with("obstacle") && event.pair.1.starts_with("obstacle") {
-            let mut obstacle1: Option<&mut Obstacle> = Option::None;
-            let mut obstacle2: Option<&mut Obstacle> = Option::None;
-            for obstacle in state.obstacles.iter_mut() {
-                if obstacle.label == event.pair.0 {
-                    obstacle1 = Some(obstacle);
-                } else if obstacle.label == event.pair.1 {
-                    obstacle2 = Some(obstacle);
-                }
+        }
+
+        if self.leaderboard_dirty {
+            save_leaderboard(&self.leaderboard);
+            self.render_leaderboard(engine);
+            self.leaderboard_dirty = false;
+        }
+
+        // Handle collisions
+        for event in engine.collision_events.drain(..) {
+            if event.state.is_end() {
+                continue;
             }
-            if obstacle1.is_some() && obstacle2.is_some() {
-                let this = obstacle1.unwrap();
-                let other = obstacle2.unwrap();
-                let this_sprite = engine.sprites.get(&this.label).unwrap();
-                let other_sprite = engine.sprites.get(&other.label).unwrap();
-                this.bounce(other, this_sprite, other_sprite);
+
+            if event.pair.one_starts_with("player-head") {
+                println!("Collision with player: {:?}", event.pair);
+                // Figure out which side is the player and which is what the player collided with
+                let player_label;
+                let colliding_with_label;
+                if event.pair.0.starts_with("player-head") {
+                    player_label = event.pair.0;
+                    colliding_with_label = event.pair.1;
+                } else {
+                    player_label = event.pair.1;
+                    colliding_with_label = event.pair.0;
+                }
+
+                // Get player object based on label name
+                let idx = (player_label.strip_prefix("player-head").unwrap().chars().nth(0).unwrap() as u8 - '0' as u8) as usize;
+                let player = &mut self.player[idx];
+
+                // Sprites linger for PLAYER_LOOSE_TIMEOUT after a player has lost, so guard
+                // against still counting collisions that land on a player who's already dead.
+                let was_playing = player.is_playing();
+
+                // If pill, then eat it, otherwise loose
+                if colliding_with_label.starts_with("pill") {
+                    player.max_len += 1;
+                    if was_playing {
+                        self.stats[idx].pills += 1;
+                        self.stats[idx].collisions += 1;
+                    }
+                    engine.sprites.remove(&colliding_with_label);
+                    engine.audio_manager.play_sfx(SfxPreset::Confirmation1, 0.2);
+                } else {
+                    player.lost();
+                    if was_playing {
+                        self.stats[idx].collisions += 1;
+                    }
+                    engine.audio_manager.play_sfx(SfxPreset::Impact1, 0.2);
+                    println!("{} lost", player_label);
+                }
+            } else if event.pair.0.starts_with("obstacle") && event.pair.1.starts_with("obstacle") {
+                let mut obstacle1: Option<&mut Obstacle> = Option::None;
+                let mut obstacle2: Option<&mut Obstacle> = Option::None;
+                for obstacle in self.obstacles.iter_mut() {
+                    if obstacle.label == event.pair.0 {
+                        obstacle1 = Some(obstacle);
+                    } else if obstacle.label == event.pair.1 {
+                        obstacle2 = Some(obstacle);
+                    }
+                }
+                if obstacle1.is_some() && obstacle2.is_some() {
+                    let this = obstacle1.unwrap();
+                    let other = obstacle2.unwrap();
+                    let this_sprite = engine.sprites.get(&this.label).unwrap();
+                    let other_sprite = engine.sprites.get(&other.label).unwrap();
+                    this.bounce(other, this_sprite, other_sprite);
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrap_position_passes_through_within_bounds() {
+        let dims = Vec2::new(200.0, 100.0);
+        let pos = Vec2::new(10.0, -20.0);
+        assert_eq!(wrap_position(pos, dims), pos);
+    }
+
+    #[test]
+    fn wrap_position_wraps_past_each_edge() {
+        let dims = Vec2::new(200.0, 100.0);
+        assert_eq!(wrap_position(Vec2::new(101.0, 0.0), dims), Vec2::new(-100.0, 0.0));
+        assert_eq!(wrap_position(Vec2::new(-101.0, 0.0), dims), Vec2::new(100.0, 0.0));
+        assert_eq!(wrap_position(Vec2::new(0.0, 51.0), dims), Vec2::new(0.0, -50.0));
+        assert_eq!(wrap_position(Vec2::new(0.0, -51.0), dims), Vec2::new(0.0, 50.0));
+    }
+
+    #[test]
+    fn direction_heading_matches_cardinal_angles() {
+        assert_eq!(direction_heading(Direction::RIGHT), 0.0);
+        assert_eq!(direction_heading(Direction::UP), FRAC_PI_2);
+        assert_eq!(direction_heading(Direction::LEFT), PI);
+        assert_eq!(direction_heading(Direction::DOWN), -FRAC_PI_2);
+    }
+
+    #[test]
+    fn tail_owner_parses_owning_player_index() {
+        assert_eq!(tail_owner("player-tail2.17"), Some(2));
+        assert_eq!(tail_owner("player-tail0.0"), Some(0));
+    }
+
+    #[test]
+    fn tail_owner_rejects_non_tail_labels() {
+        assert_eq!(tail_owner("obstacle3"), None);
+        assert_eq!(tail_owner("pill5"), None);
+        assert_eq!(tail_owner("player-head1"), None);
+    }
+
+    #[test]
+    fn leaderboard_keeps_only_top_leaderboard_size_entries_sorted_descending() {
+        let mut board = Leaderboard::default();
+        for score in 0..(LEADERBOARD_SIZE + 5) {
+            board.entries.push(HighScore { score, timestamp: 0 });
+        }
+        board.entries.sort_by(|a, b| b.score.cmp(&a.score));
+        board.entries.truncate(LEADERBOARD_SIZE);
+
+        assert_eq!(board.entries.len(), LEADERBOARD_SIZE);
+        let scores: Vec<usize> = board.entries.iter().map(|entry| entry.score).collect();
+        let expected: Vec<usize> = ((LEADERBOARD_SIZE + 5 - LEADERBOARD_SIZE)..(LEADERBOARD_SIZE + 5)).rev().collect();
+        assert_eq!(scores, expected);
+    }
+
+    #[test]
+    fn should_end_round_requires_two_joined_and_at_most_one_playing() {
+        assert!(!should_end_round(1, 1));
+        assert!(!should_end_round(2, 2));
+        assert!(should_end_round(2, 1));
+        assert!(should_end_round(3, 0));
+    }
+}